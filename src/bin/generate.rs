@@ -1,11 +1,28 @@
 use std::collections::BTreeSet;
+use std::sync::Arc;
+use std::time::Duration;
 
+use anyhow::Context;
 use askama::Template;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{Html, IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use serde::{Deserialize, Deserializer, Serialize};
 use sqlx::Connection;
+use tokio::sync::RwLock;
 
 use game_reviews::igdb::{self, IGDBCache};
 
-#[derive(sqlx::FromRow, Debug)]
+/// How often the in-memory snapshot of reviews is rebuilt from the sqlite
+/// database and IGDB cache while running in server mode. Shares the IGDB
+/// cache's TTL so the two can't drift out of sync.
+const REFRESH_INTERVAL: Duration = igdb::DEFAULT_REFETCH_DURATION;
+
+#[derive(sqlx::FromRow, Debug, Clone, Serialize)]
 struct Category {
     id: i64,
     title: String,
@@ -30,12 +47,13 @@ struct GameReview {
     category_id: i64,
 }
 
+#[derive(Clone, Serialize)]
 struct Section {
     category: Category,
     reviews: Vec<Review>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize)]
 struct Review {
     title: String,
     link: String,
@@ -52,8 +70,8 @@ struct Review {
 
 #[derive(Template)]
 #[template(path = "reviews.html")]
-struct ReviewTemplate {
-    sections: Vec<Section>,
+struct ReviewTemplate<'a> {
+    sections: &'a [Section],
 }
 
 mod filters {
@@ -68,7 +86,8 @@ async fn get_sections<Cache: IGDBCache>(
     sqlite_path: &str,
     igdb: &igdb::IGDB<Cache>,
 ) -> anyhow::Result<Vec<Section>> {
-    let mut conn = sqlx::SqliteConnection::connect(sqlite_path).await?;
+    let mut conn =
+        sqlx::SqliteConnection::connect_with(&igdb::sqlite_connect_options(sqlite_path)?).await?;
 
     let categories = sqlx::query_as::<_, Category>("SELECT * from category ORDER BY sort_order")
         .fetch_all(&mut conn)
@@ -167,19 +186,540 @@ fn make_review(
 async fn main() -> anyhow::Result<()> {
     env_logger::init();
 
+    let is_static = std::env::args().any(|arg| arg == "--static");
+
     // let cache = igdb::NoOpCache {};
     let sqlite_path = "game_reviews.sqlite3";
-    let cache = igdb::SqliteCache::new(sqlite_path.to_string());
+    let cache = igdb::SqliteCache::new(sqlite_path).await?;
     let igdb = igdb::IGDB::new(cache).await?;
     let sections = get_sections(sqlite_path, &igdb).await?;
-    let total_count: usize = sections.iter().map(|s| s.reviews.len()).sum();
 
-    let mut wrt = std::io::BufWriter::new(std::io::stdout());
-    let reviews = ReviewTemplate { sections };
-    reviews.write_into(&mut wrt)?;
-    log::info!("Generated reviews for {} games", total_count);
+    if is_static {
+        let total_count: usize = sections.iter().map(|s| s.reviews.len()).sum();
+        let mut wrt = std::io::BufWriter::new(std::io::stdout());
+        let reviews = ReviewTemplate {
+            sections: &sections,
+        };
+        reviews.write_into(&mut wrt)?;
+        log::info!("Generated reviews for {} games", total_count);
+        return Ok(());
+    }
+
+    let db = sqlx::SqlitePool::connect_with(igdb::sqlite_connect_options(sqlite_path)?).await?;
+    let jwt_secret = std::env::var("JWT_SECRET").context("env var JWT_SECRET not found")?;
+    let admin = server::AdminCredentials {
+        username: std::env::var("ADMIN_USERNAME")
+            .context("env var ADMIN_USERNAME not found")?,
+        password: std::env::var("ADMIN_PASSWORD")
+            .context("env var ADMIN_PASSWORD not found")?,
+    };
+
+    let igdb = Arc::new(igdb);
+    let state = server::AppState::new(sections, db, Arc::clone(&igdb), jwt_secret, admin);
+
+    {
+        let state = state.clone();
+        let igdb = Arc::clone(&igdb);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(REFRESH_INTERVAL);
+            interval.tick().await; // first tick fires immediately, we already have a fresh snapshot
+            loop {
+                interval.tick().await;
+                match get_sections(sqlite_path, &igdb).await {
+                    Ok(sections) => state.replace(sections).await,
+                    Err(err) => log::error!("failed to refresh reviews snapshot: {err:?}"),
+                }
+            }
+        });
+    }
+
+    let addr = std::env::var("LISTEN_ADDR").unwrap_or_else(|_| "0.0.0.0:8080".to_string());
+    log::info!("Listening on {addr}");
+    server::serve(&addr, state).await
+}
+
+mod server {
+    use super::*;
+
+    pub struct AdminCredentials {
+        pub username: String,
+        pub password: String,
+    }
+
+    #[derive(Clone)]
+    pub struct AppState {
+        sections: Arc<RwLock<Arc<Vec<Section>>>>,
+        db: sqlx::SqlitePool,
+        igdb: Arc<igdb::IGDB<igdb::SqliteCache>>,
+        jwt_secret: Arc<str>,
+        admin: Arc<AdminCredentials>,
+    }
+
+    impl AppState {
+        pub fn new(
+            sections: Vec<Section>,
+            db: sqlx::SqlitePool,
+            igdb: Arc<igdb::IGDB<igdb::SqliteCache>>,
+            jwt_secret: String,
+            admin: AdminCredentials,
+        ) -> Self {
+            Self {
+                sections: Arc::new(RwLock::new(Arc::new(sections))),
+                db,
+                igdb,
+                jwt_secret: jwt_secret.into(),
+                admin: Arc::new(admin),
+            }
+        }
+
+        async fn snapshot(&self) -> Arc<Vec<Section>> {
+            self.sections.read().await.clone()
+        }
+
+        pub async fn replace(&self, sections: Vec<Section>) {
+            *self.sections.write().await = Arc::new(sections);
+        }
+    }
+
+    struct AppError {
+        status: StatusCode,
+        err: anyhow::Error,
+    }
+
+    impl AppError {
+        fn with_status(status: StatusCode, msg: impl Into<String>) -> Self {
+            Self {
+                status,
+                err: anyhow::anyhow!(msg.into()),
+            }
+        }
+
+        fn unauthorized(msg: impl Into<String>) -> Self {
+            Self::with_status(StatusCode::UNAUTHORIZED, msg)
+        }
+
+        fn bad_request(msg: impl Into<String>) -> Self {
+            Self::with_status(StatusCode::BAD_REQUEST, msg)
+        }
+
+        fn not_found(msg: impl Into<String>) -> Self {
+            Self::with_status(StatusCode::NOT_FOUND, msg)
+        }
+    }
+
+    impl IntoResponse for AppError {
+        fn into_response(self) -> Response {
+            if self.status == StatusCode::INTERNAL_SERVER_ERROR {
+                log::error!("request failed: {:?}", self.err);
+                (self.status, "internal server error").into_response()
+            } else {
+                (self.status, self.err.to_string()).into_response()
+            }
+        }
+    }
+
+    impl<E: Into<anyhow::Error>> From<E> for AppError {
+        fn from(err: E) -> Self {
+            Self {
+                status: StatusCode::INTERNAL_SERVER_ERROR,
+                err: err.into(),
+            }
+        }
+    }
+
+    async fn index(State(state): State<AppState>) -> Result<Html<String>, AppError> {
+        let sections = state.snapshot().await;
+        let reviews = super::ReviewTemplate {
+            sections: &sections,
+        };
+        Ok(Html(reviews.render()?))
+    }
+
+    async fn healthz() -> &'static str {
+        "ok"
+    }
+
+    async fn api_reviews(State(state): State<AppState>) -> Json<Vec<Section>> {
+        Json((*state.snapshot().await).clone())
+    }
+
+    async fn metrics() -> Result<String, AppError> {
+        Ok(game_reviews::metrics::encode()?)
+    }
+
+    #[derive(Deserialize)]
+    struct LoginRequest {
+        username: String,
+        password: String,
+    }
 
-    Ok(())
+    #[derive(Serialize)]
+    struct LoginResponse {
+        token: String,
+    }
+
+    async fn login(
+        State(state): State<AppState>,
+        Json(body): Json<LoginRequest>,
+    ) -> Result<Json<LoginResponse>, AppError> {
+        // Not constant-time, so in principle vulnerable to a timing attack.
+        // Acceptable for now: this is the only account on a single-admin site.
+        if body.username != state.admin.username || body.password != state.admin.password {
+            return Err(AppError::unauthorized("invalid credentials"));
+        }
+        let token = auth::issue_token(&body.username, &state.jwt_secret)?;
+        Ok(Json(LoginResponse { token }))
+    }
+
+    #[derive(Deserialize)]
+    struct CreateReviewRequest {
+        igdb_id: u32,
+        category_id: i64,
+        title: String,
+        year_played: Option<String>,
+        rating: Option<i64>,
+        description: String,
+        pros: Option<String>,
+        cons: Option<String>,
+        heart_count: Option<i64>,
+    }
+
+    async fn create_review(
+        State(state): State<AppState>,
+        _user: auth::AuthUser,
+        Json(body): Json<CreateReviewRequest>,
+    ) -> Result<(StatusCode, Json<i64>), AppError> {
+        // also warms the cache for this game
+        let games = state.igdb.get_games(&[body.igdb_id]).await?;
+        if games.is_empty() {
+            return Err(AppError::bad_request(format!(
+                "igdb_id {} does not resolve to an IGDB game",
+                body.igdb_id
+            )));
+        }
+
+        let id = sqlx::query(
+            "INSERT INTO game_review
+                (igdb_id, category_id, title, year_played, rating, description, pros, cons, heart_count)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(body.igdb_id)
+        .bind(body.category_id)
+        .bind(&body.title)
+        .bind(&body.year_played)
+        .bind(body.rating)
+        .bind(&body.description)
+        .bind(&body.pros)
+        .bind(&body.cons)
+        .bind(body.heart_count)
+        .execute(&state.db)
+        .await?
+        .last_insert_rowid();
+
+        Ok((StatusCode::CREATED, Json(id)))
+    }
+
+    /// Distinguishes "field absent from the request body" (outer `None`,
+    /// leave the column untouched) from "field explicitly set to `null`"
+    /// (outer `Some(None)`, clear the column), which a plain `Option<T>`
+    /// can't do since both cases deserialize to `None`.
+    fn double_option<'de, T, D>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+    where
+        T: Deserialize<'de>,
+        D: Deserializer<'de>,
+    {
+        Deserialize::deserialize(deserializer).map(Some)
+    }
+
+    #[derive(Deserialize, Default)]
+    struct UpdateReviewRequest {
+        category_id: Option<i64>,
+        title: Option<String>,
+        #[serde(default, deserialize_with = "double_option")]
+        year_played: Option<Option<String>>,
+        #[serde(default, deserialize_with = "double_option")]
+        rating: Option<Option<i64>>,
+        description: Option<String>,
+        #[serde(default, deserialize_with = "double_option")]
+        pros: Option<Option<String>>,
+        #[serde(default, deserialize_with = "double_option")]
+        cons: Option<Option<String>>,
+        #[serde(default, deserialize_with = "double_option")]
+        heart_count: Option<Option<i64>>,
+    }
+
+    async fn update_review(
+        State(state): State<AppState>,
+        _user: auth::AuthUser,
+        Path(id): Path<i64>,
+        Json(body): Json<UpdateReviewRequest>,
+    ) -> Result<StatusCode, AppError> {
+        let mut query_builder = sqlx::QueryBuilder::new("UPDATE game_review SET ");
+        let mut any_field = false;
+        {
+            let mut separated = query_builder.separated(", ");
+            macro_rules! set_if_present {
+                ($field:literal, $val:expr) => {
+                    if let Some(v) = $val {
+                        separated.push(concat!($field, " = "));
+                        separated.push_bind_unseparated(v);
+                        any_field = true;
+                    }
+                };
+            }
+            set_if_present!("category_id", body.category_id);
+            set_if_present!("title", body.title);
+            set_if_present!("year_played", body.year_played);
+            set_if_present!("rating", body.rating);
+            set_if_present!("description", body.description);
+            set_if_present!("pros", body.pros);
+            set_if_present!("cons", body.cons);
+            set_if_present!("heart_count", body.heart_count);
+        }
+
+        if !any_field {
+            return Ok(StatusCode::NO_CONTENT);
+        }
+
+        query_builder.push(" WHERE id = ");
+        query_builder.push_bind(id);
+
+        let result = query_builder.build().execute(&state.db).await?;
+        if result.rows_affected() == 0 {
+            return Err(AppError::not_found(format!("no review with id {id}")));
+        }
+
+        Ok(StatusCode::NO_CONTENT)
+    }
+
+    async fn delete_review(
+        State(state): State<AppState>,
+        _user: auth::AuthUser,
+        Path(id): Path<i64>,
+    ) -> Result<StatusCode, AppError> {
+        let result = sqlx::query("DELETE FROM game_review WHERE id = ?")
+            .bind(id)
+            .execute(&state.db)
+            .await?;
+        if result.rows_affected() == 0 {
+            return Err(AppError::not_found(format!("no review with id {id}")));
+        }
+        Ok(StatusCode::NO_CONTENT)
+    }
+
+    async fn list_categories(
+        State(state): State<AppState>,
+    ) -> Result<Json<Vec<Category>>, AppError> {
+        let categories = sqlx::query_as::<_, Category>("SELECT * FROM category ORDER BY sort_order")
+            .fetch_all(&state.db)
+            .await?;
+        Ok(Json(categories))
+    }
+
+    async fn get_category(
+        State(state): State<AppState>,
+        Path(id): Path<i64>,
+    ) -> Result<Json<Category>, AppError> {
+        let category = sqlx::query_as::<_, Category>("SELECT * FROM category WHERE id = ?")
+            .bind(id)
+            .fetch_optional(&state.db)
+            .await?
+            .ok_or_else(|| AppError::not_found(format!("no category with id {id}")))?;
+        Ok(Json(category))
+    }
+
+    #[derive(Deserialize)]
+    struct CreateCategoryRequest {
+        title: String,
+        sort_order: i64,
+        description: String,
+    }
+
+    async fn create_category(
+        State(state): State<AppState>,
+        _user: auth::AuthUser,
+        Json(body): Json<CreateCategoryRequest>,
+    ) -> Result<(StatusCode, Json<i64>), AppError> {
+        let id = sqlx::query("INSERT INTO category (title, sort_order, description) VALUES (?, ?, ?)")
+            .bind(&body.title)
+            .bind(body.sort_order)
+            .bind(&body.description)
+            .execute(&state.db)
+            .await?
+            .last_insert_rowid();
+
+        Ok((StatusCode::CREATED, Json(id)))
+    }
+
+    #[derive(Deserialize, Default)]
+    struct UpdateCategoryRequest {
+        title: Option<String>,
+        sort_order: Option<i64>,
+        description: Option<String>,
+    }
+
+    async fn update_category(
+        State(state): State<AppState>,
+        _user: auth::AuthUser,
+        Path(id): Path<i64>,
+        Json(body): Json<UpdateCategoryRequest>,
+    ) -> Result<StatusCode, AppError> {
+        let mut query_builder = sqlx::QueryBuilder::new("UPDATE category SET ");
+        let mut any_field = false;
+        {
+            let mut separated = query_builder.separated(", ");
+            if let Some(v) = body.title {
+                separated.push("title = ");
+                separated.push_bind_unseparated(v);
+                any_field = true;
+            }
+            if let Some(v) = body.sort_order {
+                separated.push("sort_order = ");
+                separated.push_bind_unseparated(v);
+                any_field = true;
+            }
+            if let Some(v) = body.description {
+                separated.push("description = ");
+                separated.push_bind_unseparated(v);
+                any_field = true;
+            }
+        }
+
+        if !any_field {
+            return Ok(StatusCode::NO_CONTENT);
+        }
+
+        query_builder.push(" WHERE id = ");
+        query_builder.push_bind(id);
+
+        let result = query_builder.build().execute(&state.db).await?;
+        if result.rows_affected() == 0 {
+            return Err(AppError::not_found(format!("no category with id {id}")));
+        }
+
+        Ok(StatusCode::NO_CONTENT)
+    }
+
+    async fn delete_category(
+        State(state): State<AppState>,
+        _user: auth::AuthUser,
+        Path(id): Path<i64>,
+    ) -> Result<StatusCode, AppError> {
+        let result = sqlx::query("DELETE FROM category WHERE id = ?")
+            .bind(id)
+            .execute(&state.db)
+            .await?;
+        if result.rows_affected() == 0 {
+            return Err(AppError::not_found(format!("no category with id {id}")));
+        }
+        Ok(StatusCode::NO_CONTENT)
+    }
+
+    pub async fn serve(addr: &str, state: AppState) -> anyhow::Result<()> {
+        let app = Router::new()
+            .route("/", get(index))
+            .route("/healthz", get(healthz))
+            .route("/api/reviews", get(api_reviews).post(create_review))
+            .route("/metrics", get(metrics))
+            .route("/auth/login", post(login))
+            .route(
+                "/api/reviews/:id",
+                axum::routing::patch(update_review).delete(delete_review),
+            )
+            .route(
+                "/api/categories",
+                get(list_categories).post(create_category),
+            )
+            .route(
+                "/api/categories/:id",
+                get(get_category)
+                    .patch(update_category)
+                    .delete(delete_category),
+            )
+            .with_state(state);
+
+        let listener = tokio::net::TcpListener::bind(addr).await?;
+        axum::serve(listener, app).await?;
+        Ok(())
+    }
+
+    /// JWT issuance and the bearer-token request guard.
+    mod auth {
+        use std::time::{SystemTime, UNIX_EPOCH};
+
+        use axum::{
+            extract::FromRequestParts,
+            http::{request::Parts, StatusCode},
+        };
+        use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+        use serde::{Deserialize, Serialize};
+
+        use super::AppState;
+
+        const TOKEN_TTL_SECS: u64 = 60 * 60;
+
+        #[derive(Serialize, Deserialize)]
+        struct Claims {
+            sub: String,
+            exp: u64,
+        }
+
+        pub fn issue_token(subject: &str, secret: &str) -> anyhow::Result<String> {
+            let exp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() + TOKEN_TTL_SECS;
+            let claims = Claims {
+                sub: subject.to_string(),
+                exp,
+            };
+            let token = encode(
+                &Header::default(),
+                &claims,
+                &EncodingKey::from_secret(secret.as_bytes()),
+            )?;
+            Ok(token)
+        }
+
+        /// Extractor that rejects the request with 401 unless it carries a
+        /// valid, unexpired bearer token signed with the server's JWT secret.
+        pub struct AuthUser {
+            #[allow(dead_code)]
+            pub subject: String,
+        }
+
+        pub struct AuthRejection;
+
+        impl axum::response::IntoResponse for AuthRejection {
+            fn into_response(self) -> axum::response::Response {
+                (StatusCode::UNAUTHORIZED, "unauthorized").into_response()
+            }
+        }
+
+        impl FromRequestParts<AppState> for AuthUser {
+            type Rejection = AuthRejection;
+
+            async fn from_request_parts(
+                parts: &mut Parts,
+                state: &AppState,
+            ) -> Result<Self, Self::Rejection> {
+                let token = parts
+                    .headers
+                    .get(axum::http::header::AUTHORIZATION)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|v| v.strip_prefix("Bearer "))
+                    .ok_or(AuthRejection)?;
+
+                let data = decode::<Claims>(
+                    token,
+                    &DecodingKey::from_secret(state.jwt_secret.as_bytes()),
+                    &Validation::default(),
+                )
+                .map_err(|_| AuthRejection)?;
+
+                Ok(AuthUser {
+                    subject: data.claims.sub,
+                })
+            }
+        }
+    }
 }
 
 #[cfg(test)]
@@ -1,4 +1,4 @@
-use std::{collections::HashMap, num::NonZeroU32};
+use std::{collections::HashMap, num::NonZeroU32, str::FromStr, time::Duration};
 
 use anyhow::Context;
 use async_trait::async_trait;
@@ -11,9 +11,79 @@ use governor::{
 use hyper::{client::HttpConnector, Body, Client, Method, Request, Uri};
 use hyper_tls::HttpsConnector;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use sqlx::{Connection, Transaction};
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode};
+use sqlx::Transaction;
 use time::OffsetDateTime;
 
+/// How long a connection waits on a locked sqlite database before giving up
+/// with `SQLITE_BUSY`.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Connection options shared by every handle this crate opens against the
+/// reviews sqlite database (the cache pool, the write-API pool, and the
+/// read-only connection used to build the reviews snapshot): WAL journaling
+/// plus a busy timeout so concurrent readers and writers don't trip over
+/// each other with "database is locked" errors now that this runs as a
+/// long-lived service instead of a one-shot CLI.
+pub fn sqlite_connect_options(path: &str) -> anyhow::Result<SqliteConnectOptions> {
+    Ok(SqliteConnectOptions::from_str(path)?
+        .journal_mode(SqliteJournalMode::Wal)
+        .busy_timeout(BUSY_TIMEOUT))
+}
+
+/// Default TTL for a cached IGDB object before it's considered stale and
+/// eligible for a background refetch. Also used by `generate`'s in-memory
+/// reviews snapshot as its refresh interval, so the two stay in sync: there's
+/// no point rebuilding the snapshot more often than the cache entries behind
+/// it can actually change.
+pub const DEFAULT_REFETCH_DURATION: Duration = Duration::from_secs(30 * 60);
+
+/// Maximum number of rows IGDB returns for a single request, and the largest
+/// id filter that can be sent in one query.
+const MAX_PAGE_SIZE: usize = 500;
+
+/// Outcome of a cache lookup, taking the entry's age into account.
+#[derive(Debug)]
+pub enum MaybeCached<T> {
+    /// Found, and younger than the configured `refetch_duration`.
+    Fresh(T),
+    /// Found, but old enough that it should be refetched. Still usable as a
+    /// fallback if the refetch fails.
+    Stale(T),
+    /// No cache entry at all for this id.
+    Missing,
+}
+
+/// Turn a (possibly absent) `fetched_at` timestamp into a `MaybeCached`
+/// wrapper. A NULL/absent `fetched_at` (e.g. a row written before this
+/// column existed) is treated as infinitely stale. Negative ages, which can
+/// happen with clock skew, are clamped to zero so they don't look fresher
+/// than they are.
+fn freshness_of<T>(val: T, fetched_at: Option<i64>, refetch_duration: Duration) -> MaybeCached<T> {
+    let Some(fetched_at) = fetched_at else {
+        return MaybeCached::Stale(val);
+    };
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    let age = (now - fetched_at).max(0);
+    if age < refetch_duration.as_secs() as i64 {
+        MaybeCached::Fresh(val)
+    } else {
+        MaybeCached::Stale(val)
+    }
+}
+
+/// Whether `fetch_all_pages` should fetch another page after a page of
+/// `page_len` items came back at `offset`, and if so, the offset for that
+/// next page. A page shorter than `MAX_PAGE_SIZE` means IGDB has nothing
+/// left to return.
+fn next_page_offset(offset: usize, page_len: usize) -> Option<usize> {
+    if page_len < MAX_PAGE_SIZE {
+        None
+    } else {
+        Some(offset + MAX_PAGE_SIZE)
+    }
+}
+
 trait HasCacheId {
     fn id(&self) -> u32;
 }
@@ -86,15 +156,27 @@ pub trait IGDBCache: Sync {
     }
 
     #[allow(unused_variables)]
-    async fn get<T>(&self, id: u32, endpoint: &str) -> anyhow::Result<Option<T>>
+    async fn get<T>(
+        &self,
+        id: u32,
+        endpoint: &str,
+        refetch_duration: Duration,
+    ) -> anyhow::Result<MaybeCached<T>>
     where
         T: Send + DeserializeOwned,
     {
-        Ok(None)
+        Ok(MaybeCached::Missing)
     }
 
+    /// Returns only the ids that have a cache entry; an id absent from the
+    /// returned map has no cache entry at all (equivalent to `Missing`).
     #[allow(unused_variables)]
-    async fn get_many<T>(&self, endpoint: &str, ids: &[u32]) -> anyhow::Result<HashMap<u32, T>>
+    async fn get_many<T>(
+        &self,
+        endpoint: &str,
+        ids: &[u32],
+        refetch_duration: Duration,
+    ) -> anyhow::Result<HashMap<u32, MaybeCached<T>>>
     where
         T: Send + DeserializeOwned,
     {
@@ -110,17 +192,57 @@ pub struct NoOpCache {}
 impl IGDBCache for NoOpCache {}
 
 pub struct SqliteCache {
-    path: String,
+    pool: sqlx::SqlitePool,
 }
 
 impl SqliteCache {
-    pub fn new(path: String) -> Self {
-        Self { path }
+    pub async fn new(path: &str) -> anyhow::Result<Self> {
+        let pool = sqlx::SqlitePool::connect_with(sqlite_connect_options(path)?).await?;
+        Self::ensure_fetched_at_column(&pool).await?;
+        Self::ensure_unique_index(&pool).await?;
+        Ok(Self { pool })
     }
 
-    async fn get_conn(&self) -> anyhow::Result<sqlx::SqliteConnection> {
-        let conn = sqlx::SqliteConnection::connect(&self.path).await?;
-        Ok(conn)
+    /// Older `igdb_cache` tables predate the `fetched_at` column entirely,
+    /// not just rows with a NULL value in it. Add it if it's missing so a
+    /// plain `SELECT ... fetched_at` doesn't fail outright on those
+    /// databases; a database that already has the column just hits the
+    /// "duplicate column" error, which we treat as a no-op.
+    async fn ensure_fetched_at_column(pool: &sqlx::SqlitePool) -> anyhow::Result<()> {
+        match sqlx::query("ALTER TABLE igdb_cache ADD COLUMN fetched_at INTEGER")
+            .execute(pool)
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(sqlx::Error::Database(err)) if err.message().contains("duplicate column name") => {
+                Ok(())
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// `_set` relies on `INSERT OR REPLACE` to upsert a cache entry, which
+    /// only dedupes against an existing `UNIQUE` constraint on
+    /// `(igdb_id, endpoint)`. Older tables predate that constraint, so
+    /// first collapse any duplicate rows down to the most recently fetched
+    /// one before creating the index, otherwise the `CREATE UNIQUE INDEX`
+    /// itself would fail on the leftover duplicates.
+    async fn ensure_unique_index(pool: &sqlx::SqlitePool) -> anyhow::Result<()> {
+        sqlx::query(
+            "DELETE FROM igdb_cache
+             WHERE rowid NOT IN (
+                 SELECT MAX(rowid) FROM igdb_cache GROUP BY igdb_id, endpoint
+             )",
+        )
+        .execute(pool)
+        .await?;
+        sqlx::query(
+            "CREATE UNIQUE INDEX IF NOT EXISTS igdb_cache_igdb_id_endpoint
+             ON igdb_cache (igdb_id, endpoint)",
+        )
+        .execute(pool)
+        .await?;
+        Ok(())
     }
 
     async fn _set<'a, T>(
@@ -134,12 +256,16 @@ impl SqliteCache {
         T: Send + Serialize,
     {
         let val = serde_json::to_string(&val)?;
-        sqlx::query("INSERT INTO igdb_cache (igdb_id, endpoint, value) VALUES (?,?,?)")
-            .bind(id)
-            .bind(endpoint)
-            .bind(val)
-            .execute(tx)
-            .await?;
+        let fetched_at = OffsetDateTime::now_utc().unix_timestamp();
+        sqlx::query(
+            "INSERT OR REPLACE INTO igdb_cache (igdb_id, endpoint, value, fetched_at) VALUES (?,?,?,?)",
+        )
+        .bind(id)
+        .bind(endpoint)
+        .bind(val)
+        .bind(fetched_at)
+        .execute(tx)
+        .await?;
         log::debug!("set cache for ({endpoint}, {id})");
         Ok(())
     }
@@ -151,8 +277,7 @@ impl IGDBCache for SqliteCache {
     where
         T: Send + Serialize,
     {
-        let mut conn = self.get_conn().await?;
-        let mut tx = conn.begin().await?;
+        let mut tx = self.pool.begin().await?;
         self._set(&mut tx, id, endpoint, val).await?;
         tx.commit().await?;
         Ok(())
@@ -163,8 +288,7 @@ impl IGDBCache for SqliteCache {
         T: Send + Serialize,
     {
         log::debug!("set {} objects for endpoint {}", vals.len(), endpoint);
-        let mut conn = self.get_conn().await?;
-        let mut tx = conn.begin().await?;
+        let mut tx = self.pool.begin().await?;
 
         for (id, val) in vals {
             self._set(&mut tx, id, endpoint, val).await?;
@@ -173,35 +297,65 @@ impl IGDBCache for SqliteCache {
         Ok(())
     }
 
-    async fn get<T>(&self, id: u32, endpoint: &str) -> anyhow::Result<Option<T>>
+    async fn get<T>(
+        &self,
+        id: u32,
+        endpoint: &str,
+        refetch_duration: Duration,
+    ) -> anyhow::Result<MaybeCached<T>>
     where
         T: DeserializeOwned,
     {
-        let mut conn = self.get_conn().await?;
-        let raw_val = sqlx::query_as::<_, (String,)>(
-            "SELECT value FROM igdb_cache WHERE igdb_id = ? AND endpoint = ?",
+        let row = sqlx::query_as::<_, (String, Option<i64>)>(
+            "SELECT value, fetched_at FROM igdb_cache WHERE igdb_id = ? AND endpoint = ?",
         )
         .bind(id)
         .bind(endpoint)
-        .fetch_optional(&mut conn)
+        .fetch_optional(&self.pool)
         .await?;
 
-        let val = raw_val.map(|s| serde_json::from_str(&s.0)).transpose()?;
-        if val.is_none() {
+        let Some((raw_val, fetched_at)) = row else {
             log::debug!("cache miss for ({endpoint},{id})");
-        }
-        Ok(val)
+            return Ok(MaybeCached::Missing);
+        };
+
+        let val: T = serde_json::from_str(&raw_val)?;
+        Ok(freshness_of(val, fetched_at, refetch_duration))
     }
 
-    async fn get_many<T>(&self, endpoint: &str, ids: &[u32]) -> anyhow::Result<HashMap<u32, T>>
+    async fn get_many<T>(
+        &self,
+        endpoint: &str,
+        ids: &[u32],
+        refetch_duration: Duration,
+    ) -> anyhow::Result<HashMap<u32, MaybeCached<T>>>
     where
         T: Send + DeserializeOwned,
     {
-        let mut result = HashMap::new();
+        if ids.is_empty() {
+            return Ok(HashMap::with_capacity(0));
+        }
+
+        let mut query_builder = sqlx::QueryBuilder::new(
+            "SELECT igdb_id, value, fetched_at FROM igdb_cache WHERE endpoint = ",
+        );
+        query_builder.push_bind(endpoint);
+        query_builder.push(" AND igdb_id IN (");
+        let mut separated = query_builder.separated(", ");
         for id in ids {
-            if let Some(val) = self.get(*id, endpoint).await? {
-                result.insert(*id, val);
-            }
+            separated.push_bind(*id);
+        }
+        separated.push_unseparated(")");
+
+        let rows = query_builder
+            .build_query_as::<(u32, String, Option<i64>)>()
+            .fetch_all(&self.pool)
+            .await?;
+
+        let mut result = HashMap::with_capacity(rows.len());
+        for (id, raw_val, fetched_at) in rows {
+            let val: T = serde_json::from_str(&raw_val)?;
+            result.insert(id, freshness_of(val, fetched_at, refetch_duration));
         }
 
         Ok(result)
@@ -216,6 +370,7 @@ pub struct IGDB<Cache> {
     access_token: String,
     limiter: RateLimiter<NotKeyed, InMemoryState, DefaultClock, NoOpMiddleware>,
     cache: Cache,
+    refetch_duration: Duration,
 }
 
 impl<Cache> IGDB<Cache>
@@ -223,6 +378,13 @@ where
     Cache: IGDBCache,
 {
     pub async fn new(cache: Cache) -> anyhow::Result<Self> {
+        Self::with_refetch_duration(cache, DEFAULT_REFETCH_DURATION).await
+    }
+
+    pub async fn with_refetch_duration(
+        cache: Cache,
+        refetch_duration: Duration,
+    ) -> anyhow::Result<Self> {
         let client_id = std::env::var("IGDB_TWITCH_CLIENT_ID")
             .context("env var IGDB_TWITCH_CLIENT_ID not found")?;
         let client_secret = std::env::var("IGDB_TWITCH_CLIENT_SECRET")
@@ -274,6 +436,7 @@ where
             access_token,
             limiter,
             cache,
+            refetch_duration,
         })
     }
 
@@ -288,12 +451,28 @@ where
             .header("Authorization", format!("Bearer {}", self.access_token))
             .header("Accept", "application/json")
             .body(Body::from(body.clone()))?;
+
+        let wait_start = std::time::Instant::now();
         self.limiter.until_ready().await;
+        crate::metrics::RATE_LIMITER_WAIT
+            .with_label_values(&[endpoint])
+            .observe(wait_start.elapsed().as_secs_f64());
+
+        crate::metrics::IGDB_REQUESTS
+            .with_label_values(&[endpoint])
+            .inc();
+        let req_start = std::time::Instant::now();
         let mut resp = self.client.request(req).await?;
+        crate::metrics::IGDB_REQUEST_DURATION
+            .with_label_values(&[endpoint])
+            .observe(req_start.elapsed().as_secs_f64());
 
         let resp_body = hyper::body::to_bytes(resp.body_mut()).await?;
         let strbody = std::str::from_utf8(&resp_body).context("invalid utf-8 received")?;
         if !resp.status().is_success() {
+            crate::metrics::IGDB_REQUEST_ERRORS
+                .with_label_values(&[endpoint])
+                .inc();
             return Err(anyhow::anyhow!(
                 "invalid request for endpoint {endpoint} with body {body}: {strbody}"
             ));
@@ -302,6 +481,9 @@ where
         match serde_json::from_str(strbody) {
             Ok(results) => Ok(results),
             Err(err) => {
+                crate::metrics::IGDB_REQUEST_ERRORS
+                    .with_label_values(&[endpoint])
+                    .inc();
                 log::error!("Invalid json when fetching {endpoint} with body {body}. Got response: {strbody}\n{err:?}");
                 Err(err.into())
             }
@@ -317,37 +499,149 @@ where
     where
         T: Cacheable,
     {
-        let cached_items = self.cache.get_many::<T>(endpoint, &ids[..]).await?;
+        let mut cached_items = self
+            .cache
+            .get_many::<T>(endpoint, &ids[..], self.refetch_duration)
+            .await?;
+
+        // Stale and Missing ids are treated the same: both get refetched and
+        // overwritten in the cache. A stale value is kept around as a
+        // fallback in case the refetch fails.
+        let mut stale_fallback = HashMap::new();
+        let mut ids_to_fetch = Vec::new();
+        let mut fetched_items = Vec::new();
+        let mut hit_count = 0usize;
+
+        // `ids` can contain duplicates (e.g. two reviews for the same game).
+        // Removing straight from `cached_items` would make every occurrence
+        // past the first look like a miss, so only look each id up once.
+        let mut seen_ids = std::collections::HashSet::with_capacity(ids.len());
+        for id in ids {
+            if !seen_ids.insert(*id) {
+                continue;
+            }
+            match cached_items.remove(id) {
+                Some(MaybeCached::Fresh(val)) => {
+                    fetched_items.push(val);
+                    hit_count += 1;
+                }
+                Some(MaybeCached::Stale(val)) => {
+                    stale_fallback.insert(*id, val);
+                    ids_to_fetch.push(*id);
+                }
+                Some(MaybeCached::Missing) | None => ids_to_fetch.push(*id),
+            }
+        }
+
+        crate::metrics::CACHE_HITS
+            .with_label_values(&[endpoint])
+            .inc_by(hit_count as f64);
+        crate::metrics::CACHE_MISSES
+            .with_label_values(&[endpoint])
+            .inc_by(ids_to_fetch.len() as f64);
+
+        // IGDB caps `limit` at 500, so a batch of more than 500 ids needs
+        // chunking, and even a single batch can come back paginated if IGDB
+        // itself splits the response.
+        for chunk in ids_to_fetch.chunks(MAX_PAGE_SIZE) {
+            match self.fetch_all_pages::<T>(endpoint, fields, chunk).await {
+                Ok(result) => {
+                    self.cache
+                        .set_many(endpoint, result.iter().map(|g| (g.id(), g)).collect())
+                        .await?;
+
+                    let fetched_ids: std::collections::HashSet<_> =
+                        result.iter().map(|r| r.id()).collect();
+                    let missing: Vec<u32> = chunk
+                        .iter()
+                        .filter(|id| !fetched_ids.contains(id))
+                        .copied()
+                        .collect();
+                    if !missing.is_empty() {
+                        log::warn!(
+                            "PAGINATION!!!! {endpoint}: {} ids genuinely missing from IGDB: {missing:?}",
+                            missing.len()
+                        );
+                    }
+
+                    fetched_items.extend(result);
+                    // ids IGDB didn't return at all fall back to their stale
+                    // value, if there was one
+                    for id in missing {
+                        if let Some(val) = stale_fallback.remove(&id) {
+                            fetched_items.push(val);
+                        }
+                    }
+                }
+                Err(err) => {
+                    let ids_without_fallback: Vec<u32> = chunk
+                        .iter()
+                        .filter(|id| !stale_fallback.contains_key(id))
+                        .copied()
+                        .collect();
+                    if !ids_without_fallback.is_empty() {
+                        // no stale value to fall back to for at least one id in
+                        // this batch (e.g. its very first fetch) - returning a
+                        // Vec without some of the requested ids here would make
+                        // callers that look results up by id panic, so surface
+                        // the error instead
+                        return Err(err).with_context(|| {
+                            format!(
+                                "failed to fetch {endpoint} ids {ids_without_fallback:?} with no cached value to fall back to"
+                            )
+                        });
+                    }
+                    log::warn!(
+                        "failed to refetch a batch of {} ids for {endpoint}, falling back to stale cache entries: {err:?}",
+                        chunk.len()
+                    );
+                    for id in chunk {
+                        if let Some(val) = stale_fallback.remove(id) {
+                            fetched_items.push(val);
+                        }
+                    }
+                }
+            }
+        }
 
+        Ok(fetched_items)
+    }
+
+    /// Fetch every object matching `ids` from a single endpoint, following
+    /// `offset`-based pagination until a page comes back shorter than
+    /// `MAX_PAGE_SIZE`. `ids` must already fit within one `where id=(...)`
+    /// filter (at most `MAX_PAGE_SIZE` entries).
+    async fn fetch_all_pages<T>(
+        &self,
+        endpoint: &str,
+        fields: &str,
+        ids: &[u32],
+    ) -> anyhow::Result<Vec<T>>
+    where
+        T: Cacheable,
+    {
         let ids_str = ids
             .iter()
-            .filter(|i| !cached_items.contains_key(*i))
             .map(|i| i.to_string())
             .collect::<Vec<_>>()
             .join(",");
 
-        let mut fetched_items = if ids_str.is_empty() {
-            Vec::new()
-        } else {
-            // maximum limit is 500 and I don't have anything bigger than that, so
-            // avoid doing any pagination at all
-            let body = format!("limit 500; fields {fields}; where id=({});", ids_str);
-            let result: Vec<T> = self.req_igdb(endpoint, body).await?;
-            self.cache
-                .set_many(endpoint, result.iter().map(|g| (g.id(), g)).collect())
-                .await?;
-            if result.len() != ids.len() {
-                log::warn!("PAGINATION!!!! {} vs {}", result.len(), ids.len());
+        let mut results = Vec::new();
+        let mut offset = 0usize;
+        loop {
+            let body = format!(
+                "limit {MAX_PAGE_SIZE}; offset {offset}; fields {fields}; where id=({ids_str});"
+            );
+            let page: Vec<T> = self.req_igdb(endpoint, body).await?;
+            let page_len = page.len();
+            results.extend(page);
+            match next_page_offset(offset, page_len) {
+                Some(next_offset) => offset = next_offset,
+                None => break,
             }
-            result
-        };
-
-        fetched_items.reserve(cached_items.len());
-        for (_, cg) in cached_items {
-            fetched_items.push(cg);
         }
 
-        Ok(fetched_items)
+        Ok(results)
     }
 
     pub async fn get_games(&self, ids: &[u32]) -> anyhow::Result<Vec<Game>> {
@@ -366,3 +660,53 @@ where
         Ok(covers)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn freshness_of_fresh_within_refetch_duration() {
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        let refetch_duration = Duration::from_secs(30 * 60);
+        let result = freshness_of("val", Some(now - 10), refetch_duration);
+        assert!(matches!(result, MaybeCached::Fresh("val")));
+    }
+
+    #[test]
+    fn freshness_of_stale_past_refetch_duration() {
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        let refetch_duration = Duration::from_secs(30 * 60);
+        let result = freshness_of("val", Some(now - 31 * 60), refetch_duration);
+        assert!(matches!(result, MaybeCached::Stale("val")));
+    }
+
+    #[test]
+    fn freshness_of_missing_fetched_at_is_stale() {
+        let result = freshness_of("val", None, Duration::from_secs(30 * 60));
+        assert!(matches!(result, MaybeCached::Stale("val")));
+    }
+
+    #[test]
+    fn freshness_of_clamps_negative_age_from_clock_skew() {
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        let refetch_duration = Duration::from_secs(30 * 60);
+        // fetched_at slightly in the future: age would be negative without clamping
+        let result = freshness_of("val", Some(now + 60), refetch_duration);
+        assert!(matches!(result, MaybeCached::Fresh("val")));
+    }
+
+    #[test]
+    fn next_page_offset_stops_on_short_page() {
+        assert_eq!(next_page_offset(0, MAX_PAGE_SIZE - 1), None);
+    }
+
+    #[test]
+    fn next_page_offset_continues_on_full_page() {
+        assert_eq!(next_page_offset(0, MAX_PAGE_SIZE), Some(MAX_PAGE_SIZE));
+        assert_eq!(
+            next_page_offset(MAX_PAGE_SIZE, MAX_PAGE_SIZE),
+            Some(2 * MAX_PAGE_SIZE)
+        );
+    }
+}
@@ -0,0 +1,2 @@
+pub mod igdb;
+pub mod metrics;
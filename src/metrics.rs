@@ -0,0 +1,66 @@
+use once_cell::sync::Lazy;
+use prometheus::{
+    register_counter_vec, register_histogram_vec, CounterVec, Encoder, HistogramVec, TextEncoder,
+};
+
+pub static CACHE_HITS: Lazy<CounterVec> = Lazy::new(|| {
+    register_counter_vec!(
+        "igdb_cache_hits_total",
+        "Number of IGDB objects served from the cache, keyed by endpoint",
+        &["endpoint"]
+    )
+    .unwrap()
+});
+
+pub static CACHE_MISSES: Lazy<CounterVec> = Lazy::new(|| {
+    register_counter_vec!(
+        "igdb_cache_misses_total",
+        "Number of IGDB objects not found (or stale) in the cache, keyed by endpoint",
+        &["endpoint"]
+    )
+    .unwrap()
+});
+
+pub static IGDB_REQUESTS: Lazy<CounterVec> = Lazy::new(|| {
+    register_counter_vec!(
+        "igdb_requests_total",
+        "Number of requests made to the IGDB API, keyed by endpoint",
+        &["endpoint"]
+    )
+    .unwrap()
+});
+
+pub static IGDB_REQUEST_ERRORS: Lazy<CounterVec> = Lazy::new(|| {
+    register_counter_vec!(
+        "igdb_request_errors_total",
+        "Number of IGDB API responses that were non-2xx or not valid JSON, keyed by endpoint",
+        &["endpoint"]
+    )
+    .unwrap()
+});
+
+pub static IGDB_REQUEST_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "igdb_request_duration_seconds",
+        "Latency of requests made to the IGDB API, keyed by endpoint",
+        &["endpoint"]
+    )
+    .unwrap()
+});
+
+pub static RATE_LIMITER_WAIT: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "igdb_rate_limiter_wait_seconds",
+        "Time spent waiting on the rate limiter before an IGDB request, keyed by endpoint",
+        &["endpoint"]
+    )
+    .unwrap()
+});
+
+/// Render every registered metric in the Prometheus text exposition format.
+pub fn encode() -> anyhow::Result<String> {
+    let metric_families = prometheus::gather();
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&metric_families, &mut buffer)?;
+    Ok(String::from_utf8(buffer)?)
+}